@@ -0,0 +1,78 @@
+use std::io::Write;
+
+use flac_bound::{FlacEncoder, WriteWrapper};
+use librespot::playback::{
+    audio_backend::{Open, Sink, SinkError},
+    config::AudioFormat,
+    convert::Converter,
+    decoder::AudioPacket,
+};
+
+pub struct FileSinkFlac {
+    sink: String,
+    content: Vec<i16>,
+    compression: u32,
+}
+
+impl FileSinkFlac {
+    pub fn set_compression(&mut self, compression: u32) {
+        self.compression = compression;
+    }
+}
+
+impl Open for FileSinkFlac {
+    fn open(path: Option<String>, _audio_format: AudioFormat) -> Self {
+        let file_path = path.unwrap_or_else(|| panic!());
+        FileSinkFlac {
+            sink: file_path,
+            content: Vec::new(),
+            compression: 4,
+        }
+    }
+}
+
+impl Sink for FileSinkFlac {
+    fn start(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), SinkError> {
+        let mut flac_out_buffer = Vec::new();
+        {
+            let mut wrapper = WriteWrapper(&mut flac_out_buffer);
+            let mut encoder = FlacEncoder::new()
+                .expect("Create FLAC encoder")
+                .channels(2)
+                .bits_per_sample(16)
+                .sample_rate(44_100)
+                .compression_level(self.compression)
+                .init_write(&mut wrapper)
+                .map_err(|_| SinkError::OnWrite("Failed to initialize FLAC encoder".to_string()))?;
+
+            let frames = (self.content.len() / 2) as u32;
+            // process_interleaved wants libFLAC's FLAC__int32 samples, not our i16 PCM buffer.
+            let samples: Vec<i32> = self.content.iter().map(|&s| s as i32).collect();
+            encoder
+                .process_interleaved(&samples, frames)
+                .map_err(|_| SinkError::OnWrite("Failed to encode FLAC frames".to_string()))?;
+
+            encoder
+                .finish()
+                .map_err(|_| SinkError::OnWrite("Failed to finish FLAC stream".to_string()))?;
+        }
+
+        if let Err(e) = atomicwrites::AtomicFile::new(&self.sink, atomicwrites::OverwriteBehavior::AllowOverwrite)
+            .write(|f| f.write(&flac_out_buffer))
+        {
+            return Err(SinkError::OnWrite(e.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> Result<(), SinkError> {
+        let mut data = converter.f64_to_s16(packet.samples().unwrap());
+        self.content.append(&mut data);
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
-use std::{io::Write, path::Path};
-use mp3lame_encoder::{Builder, Id3Tag, DualPcm, FlushNoGap};
+use std::io::Write;
+use mp3lame_encoder::{Builder, DualPcm, FlushNoGap};
 
-use audiotags::{Tag, TagType};
 use librespot::playback::{
     audio_backend::{Open, Sink, SinkError},
     config::AudioFormat,
@@ -9,22 +8,20 @@ use librespot::playback::{
     decoder::AudioPacket,
 };
 
-use crate::TrackMetadata;
-
 pub struct FileSinkMP3 {
     sink: String,
     content: Vec<i16>,
-    metadata: Option<TrackMetadata>,
     compression: u32,
+    bitrate: mp3lame_encoder::Bitrate,
 }
 
 impl FileSinkMP3 {
-    pub fn add_metadata(&mut self, meta: TrackMetadata) {
-        self.metadata = Some(meta);
-    }
     pub fn set_compression(&mut self, compression: u32) {
         self.compression = compression;
     }
+    pub fn set_bitrate(&mut self, bitrate: mp3lame_encoder::Bitrate) {
+        self.bitrate = bitrate;
+    }
 }
 
 impl Open for FileSinkMP3 {
@@ -33,8 +30,8 @@ impl Open for FileSinkMP3 {
         FileSinkMP3 {
             sink: file_path,
             content: Vec::new(),
-            metadata: None,
             compression: 4,
+            bitrate: mp3lame_encoder::Bitrate::Kbps192,
         }
     }
 }
@@ -48,20 +45,8 @@ impl Sink for FileSinkMP3 {
         let mut mp3_encoder = Builder::new().expect("Create LAME builder");
         mp3_encoder.set_num_channels(2).expect("set channels");
         mp3_encoder.set_sample_rate(44_100).expect("set sample rate");
-        mp3_encoder.set_brate(mp3lame_encoder::Bitrate::Kbps192).expect("set brate");
+        mp3_encoder.set_brate(self.bitrate).expect("set brate");
         mp3_encoder.set_quality(mp3lame_encoder::Quality::Best).expect("set quality");
-        match &self.metadata {
-            Some(meta) => {
-                mp3_encoder.set_id3_tag(Id3Tag {
-                    title: meta.track_name.as_bytes(),
-                    artist: meta.artists.join(", ").as_bytes(),
-                    album: meta.album.as_bytes(),
-                    year: b"",
-                    comment: b"",
-                });
-            }
-            None => (),
-        }
         let mut mp3_encoder = mp3_encoder.build().expect("To initialize LAME encoder");
 
         // Content is interleaved, convert it to separate channels
@@ -1,12 +1,14 @@
 mod file_sink_flac;
 mod file_sink_mp3;
+mod tagging;
 
 extern crate rpassword;
 
 use librespot::core::cache::Cache;
 use librespot::core::config::SessionConfig;
 use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
+use librespot::core::file_id::FileId;
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
 use librespot::playback::config::PlayerConfig;
 use librespot::playback::mixer::NoOpVolume;
 use librespot::{core::authentication::Credentials, metadata::Playlist};
@@ -14,10 +16,12 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use librespot::playback::audio_backend::Open;
-use librespot::playback::player::Player;
+use librespot::playback::player::{Player, PlayerEvent};
 
-use librespot::metadata::{Album, Artist, Metadata, Track};
+use librespot::audio::{AudioDecrypt, AudioFile};
+use librespot::metadata::{Album, Artist, Episode, FileFormat, Image, Metadata, Show, Track};
 
+use futures::stream::StreamExt;
 use regex::Regex;
 use structopt::StructOpt;
 
@@ -37,6 +41,12 @@ struct Opt {
         help = "Your Spotify credentials cache directory"
     )]
     credentials_cache: String,
+    #[structopt(
+        short = "u",
+        long = "username",
+        help = "Your Spotify username. Prompts for a password and logs in interactively. If omitted, credentials cached from a previous login are used."
+    )]
+    username: Option<String>,
     #[structopt(
         short = "d",
         long = "destination",
@@ -56,6 +66,42 @@ struct Opt {
         help = "Delete songs from the destination that are not in the playlist."
     )]
     delete_unknown_songs: bool,
+    #[structopt(
+        short = "f",
+        long = "format",
+        default_value = "mp3",
+        help = "The output format to encode tracks as: mp3, flac or ogg (original Ogg Vorbis stream, no re-encoding)"
+    )]
+    format: String,
+    #[structopt(
+        long = "bitrate",
+        help = "MP3 bitrate in kbps (e.g. 128, 192, 320). Ignored unless --format mp3."
+    )]
+    bitrate: Option<u32>,
+    #[structopt(
+        long = "compression",
+        help = "FLAC compression level, 0 (fastest) to 8 (smallest). Ignored unless --format flac."
+    )]
+    compression: Option<u32>,
+    #[structopt(
+        short = "q",
+        long = "quality",
+        help = "A quality preset that picks format/bitrate/compression for you, overriding --format/--bitrate/--compression: best-bitrate, mp3-320, flac, ogg-vorbis"
+    )]
+    quality: Option<String>,
+    #[structopt(
+        short = "j",
+        long = "jobs",
+        default_value = "4",
+        help = "Number of tracks to download and encode concurrently"
+    )]
+    jobs: usize,
+    #[structopt(
+        long = "retries",
+        default_value = "3",
+        help = "Number of times to retry a track after a transient failure before skipping it"
+    )]
+    retries: u32,
 }
 
 #[derive(Clone)]
@@ -63,6 +109,16 @@ pub struct TrackMetadata {
     artists: Vec<String>,
     track_name: String,
     album: String,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    year: Option<i32>,
+    cover: Option<Vec<u8>>,
+}
+
+// Picks the highest-resolution cover art and fetches its bytes through the session.
+async fn fetch_cover_art(session: &Session, covers: &[Image]) -> Option<Vec<u8>> {
+    let image = covers.iter().max_by_key(|image| image.width)?;
+    session.spclient().get_image(&image.id).await.ok().map(|bytes| bytes.to_vec())
 }
 
 async fn create_session(
@@ -70,10 +126,17 @@ async fn create_session(
     credentials: Credentials,
     cache: Option<Cache>,
 ) -> Session {
-    let (s, _) = Session::connect(session_config, credentials, cache, false)
+    let (session, reusable_credentials) = Session::connect(session_config, credentials, cache.clone(), false)
         .await
         .expect("Failed to connect to Spotify");
-    s
+
+    // Persist whatever credentials we ended up authenticating with, so a password or interactive
+    // login only has to happen once and subsequent runs can use the cached blob instead.
+    if let Some(cache) = &cache {
+        cache.save_credentials(&reusable_credentials);
+    }
+
+    session
 }
 
 fn make_filename_compatible(filename: &str) -> String {
@@ -90,82 +153,240 @@ fn make_filename_compatible(filename: &str) -> String {
 #[derive(Clone, Copy, Debug)]
 pub enum Encoding {
     Flac { compression: Option<u32> },
-    Mp3,
+    Mp3 { bitrate: mp3lame_encoder::Bitrate },
+    OggVorbis,
 }
 
 fn extension_from_encoding(encoding: Encoding) -> &'static str {
     match encoding {
         Encoding::Flac { .. } => "flac",
-        Encoding::Mp3 => "mp3",
+        Encoding::Mp3 { .. } => "mp3",
+        Encoding::OggVorbis => "ogg",
     }
 }
 
-async fn download_tracks(
+// Spotify prepends a custom header before the real Ogg page data begins.
+const OGG_VORBIS_HEADER_LEN: usize = 0xA7;
+
+// Downloads the track's original Ogg Vorbis stream byte-for-byte instead of decoding and
+// re-encoding it through the Player/Sink pipeline, for a lossless, much cheaper download.
+async fn write_ogg_vorbis_passthrough(
     session: &Session,
+    id: SpotifyId,
+    files: &std::collections::HashMap<FileFormat, FileId>,
+    path: &str,
+) -> Result<(), String> {
+    use std::io::Read;
+
+    let file_id = [FileFormat::OGG_VORBIS_320, FileFormat::OGG_VORBIS_160, FileFormat::OGG_VORBIS_96]
+        .iter()
+        .find_map(|format| files.get(format).copied())
+        .ok_or_else(|| "no Ogg Vorbis file available".to_string())?;
+
+    let key = session
+        .audio_key()
+        .request(id, file_id)
+        .await
+        .map_err(|e| format!("failed to request audio key: {e}"))?;
+
+    let encrypted_file = AudioFile::open(session, file_id, 1024 * 1024)
+        .await
+        .map_err(|e| format!("failed to open audio file: {e}"))?;
+
+    let mut decrypted = AudioDecrypt::new(key, encrypted_file);
+    let mut buffer = Vec::new();
+    decrypted
+        .read_to_end(&mut buffer)
+        .map_err(|e| format!("failed to read audio stream: {e}"))?;
+
+    let stream = buffer
+        .get(OGG_VORBIS_HEADER_LEN..)
+        .ok_or_else(|| format!("audio stream shorter than the {OGG_VORBIS_HEADER_LEN}-byte Ogg Vorbis header"))?;
+
+    std::fs::write(path, stream).map_err(|e| format!("failed to write {path}: {e}"))
+}
+
+fn mp3_bitrate_from_kbps(kbps: u32) -> mp3lame_encoder::Bitrate {
+    use mp3lame_encoder::Bitrate;
+    match kbps {
+        0..=8 => Bitrate::Kbps8,
+        9..=16 => Bitrate::Kbps16,
+        17..=24 => Bitrate::Kbps24,
+        25..=32 => Bitrate::Kbps32,
+        33..=40 => Bitrate::Kbps40,
+        41..=48 => Bitrate::Kbps48,
+        49..=64 => Bitrate::Kbps64,
+        65..=80 => Bitrate::Kbps80,
+        81..=96 => Bitrate::Kbps96,
+        97..=112 => Bitrate::Kbps112,
+        113..=128 => Bitrate::Kbps128,
+        129..=144 => Bitrate::Kbps144,
+        145..=160 => Bitrate::Kbps160,
+        161..=192 => Bitrate::Kbps192,
+        193..=224 => Bitrate::Kbps224,
+        225..=256 => Bitrate::Kbps256,
+        _ => Bitrate::Kbps320,
+    }
+}
+
+// Presets borrowed from the spotty crate: a single `--quality` flag that expands
+// into the concrete encoder settings, so users don't have to know bitrate/compression numbers.
+fn encoding_from_quality_preset(preset: &str) -> Option<Encoding> {
+    match preset {
+        "best-bitrate" => Some(Encoding::Mp3 { bitrate: mp3_bitrate_from_kbps(320) }),
+        "mp3-320" => Some(Encoding::Mp3 { bitrate: mp3_bitrate_from_kbps(320) }),
+        "flac" => Some(Encoding::Flac { compression: None }),
+        "ogg-vorbis" => Some(Encoding::OggVorbis),
+        _ => None,
+    }
+}
+
+fn encoding_from_opt(opt: &Opt) -> Encoding {
+    if let Some(preset) = &opt.quality {
+        match encoding_from_quality_preset(preset) {
+            Some(encoding) => return encoding,
+            None => eprintln!("Unknown quality preset '{preset}', falling back to --format"),
+        }
+    }
+
+    match opt.format.as_str() {
+        "flac" => Encoding::Flac { compression: opt.compression },
+        "ogg" => Encoding::OggVorbis,
+        _ => Encoding::Mp3 { bitrate: mp3_bitrate_from_kbps(opt.bitrate.unwrap_or(192)) },
+    }
+}
+
+fn log_and_skip(what: &str, err: impl std::fmt::Display) {
+    eprintln!("Skipping track: failed to fetch {what}: {err}");
+}
+
+// Resolves metadata, checks for an existing file, runs the encoder and writes the result for a
+// single track. Run as one future per track so `download_tracks` can drive many of these at once.
+// Every `Metadata::get`/passthrough step that can fail returns early (just for this track) instead
+// of unwrapping, since a panic here would unwind the whole `buffer_unordered` stream and take down
+// every other in-flight download with it.
+#[allow(clippy::too_many_arguments)]
+async fn download_one_track(
+    session: Session,
+    player_config: PlayerConfig,
     destination: PathBuf,
-    tracks: Vec<SpotifyId>,
+    track: SpotifyId,
+    index: usize,
     ordered: bool,
     encoding: Encoding,
-    delete_unknown_songs: bool,
+    retries: u32,
+    all_files: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    bar: ProgressBar,
 ) {
-    let player_config = PlayerConfig::default();
-    let bar_style = ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} (ETA: {eta}) {msg}")
-        .progress_chars("##-");
-    let bar = ProgressBar::new(tracks.len() as u64);
-    bar.set_style(bar_style);
-    bar.enable_steady_tick(500);
-
-    let mut all_files: Vec<String> = vec![];
-    for (i, track) in tracks.iter().enumerate() {
-        let track_item = Track::get(&session, *track).await.unwrap();
-        let artist_name: String;
-
-        let mut metadata = TrackMetadata {
-            artists: Vec::new(),
-            track_name: track_item.name,
-            album: Album::get(session, track_item.album).await.unwrap().name,
-        };
-        if track_item.artists.len() > 1 {
-            let mut tmp: String = String::new();
-            for artist in track_item.artists {
-                let artist_item = Artist::get(&session, artist).await.unwrap();
-                metadata.artists.push(artist_item.name.clone());
-                tmp.push_str(artist_item.name.as_str());
-                tmp.push_str(", ");
+    let (metadata, files) = match track.audio_type {
+        SpotifyAudioType::Podcast => {
+            let episode = match Episode::get(&session, track).await {
+                Ok(episode) => episode,
+                Err(e) => {
+                    log_and_skip("episode metadata", e);
+                    bar.inc(1);
+                    return;
+                }
+            };
+            let show = match Show::get(&session, episode.show).await {
+                Ok(show) => show,
+                Err(e) => {
+                    log_and_skip("show metadata", e);
+                    bar.inc(1);
+                    return;
+                }
+            };
+            let cover = fetch_cover_art(&session, &show.covers).await;
+            let metadata = TrackMetadata {
+                artists: vec![show.publisher],
+                track_name: episode.name,
+                album: show.name,
+                track_number: Some(index as u32 + 1),
+                disc_number: None,
+                year: None,
+                cover,
+            };
+            (metadata, episode.files)
+        }
+        _ => {
+            let track_item = match Track::get(&session, track).await {
+                Ok(track_item) => track_item,
+                Err(e) => {
+                    log_and_skip("track metadata", e);
+                    bar.inc(1);
+                    return;
+                }
+            };
+            let album = match Album::get(&session, track_item.album).await {
+                Ok(album) => album,
+                Err(e) => {
+                    log_and_skip("album metadata", e);
+                    bar.inc(1);
+                    return;
+                }
+            };
+            let cover = fetch_cover_art(&session, &album.covers).await;
+            let mut metadata = TrackMetadata {
+                artists: Vec::new(),
+                track_name: track_item.name,
+                album: album.name,
+                track_number: Some(index as u32 + 1),
+                disc_number: Some(track_item.disc_number as u32),
+                year: Some(album.date.year),
+                cover,
+            };
+            if track_item.artists.len() > 1 {
+                for artist in track_item.artists {
+                    let artist_item = match Artist::get(&session, artist).await {
+                        Ok(artist_item) => artist_item,
+                        Err(e) => {
+                            log_and_skip("artist metadata", e);
+                            bar.inc(1);
+                            return;
+                        }
+                    };
+                    metadata.artists.push(artist_item.name);
+                }
+            } else {
+                let artist_item = match Artist::get(&session, track_item.artists[0]).await {
+                    Ok(artist_item) => artist_item,
+                    Err(e) => {
+                        log_and_skip("artist metadata", e);
+                        bar.inc(1);
+                        return;
+                    }
+                };
+                metadata.artists.push(artist_item.name);
             }
-            artist_name = String::from(tmp.trim_end_matches(", "));
-        } else {
-            artist_name = Artist::get(&session, track_item.artists[0])
-                .await
-                .unwrap()
-                .name;
-            metadata.artists.push(artist_name.clone());
+            (metadata, track_item.files)
         }
+    };
 
-        let full_track_name = format!("{} - {}", artist_name, metadata.track_name);
-        let full_track_name_clean = make_filename_compatible(full_track_name.as_str());
-        //let filename = format!("{}.flac", full_track_name_clean);
-        let filename: String;
-        let extension = extension_from_encoding(encoding);
-        if ordered {
-            filename = format!("{:03} - {full_track_name_clean}.{extension}", i + 1);
-        } else {
-            filename = format!("{full_track_name_clean}.{extension}");
-        }
-        let joined_path = destination.join(&filename);
-        let path = joined_path.to_str().unwrap();
-        bar.set_message(full_track_name_clean.as_str());
+    let full_track_name = format!("{} - {}", metadata.artists.join(", "), metadata.track_name);
+    let full_track_name_clean = make_filename_compatible(full_track_name.as_str());
+    let filename: String;
+    let extension = extension_from_encoding(encoding);
+    if ordered {
+        filename = format!("{:03} - {full_track_name_clean}.{extension}", index + 1);
+    } else {
+        filename = format!("{full_track_name_clean}.{extension}");
+    }
+    let joined_path = destination.join(&filename);
+    let path = joined_path.to_str().unwrap();
+    bar.set_message(full_track_name_clean.as_str());
 
-        let file_name = Path::new(path).file_stem().unwrap().to_str().unwrap();
-        all_files.push(file_name.to_string());
+    let file_name = Path::new(path).file_stem().unwrap().to_str().unwrap();
 
+    // Check if a file with the same name (but not necessarily the same extension) already exists.
+    // Scanning the destination directory and recording the intended filename happen under the
+    // same lock so concurrent tasks can't race each other and `delete_unknown_songs` still sees
+    // every track that was supposed to end up on disk.
+    let file_exists = {
+        let mut all_files = all_files.lock().unwrap();
         let path_parent = Path::new(path).parent().unwrap();
-        let entries = path_parent.read_dir().unwrap();
-
-        // Check if a file with the same name (but not necessarily the same extension) already exists
-        let mut file_exists = false;
-        for entry in entries {
+        // A name another task has already claimed counts as existing, even before its file is written.
+        let mut file_exists = all_files.iter().any(|f| f == file_name);
+        for entry in path_parent.read_dir().unwrap() {
             let entry = entry.unwrap();
             let entry_path = entry.path();
             let entry_file_name = entry_path.file_stem().unwrap().to_str().unwrap();
@@ -174,32 +395,148 @@ async fn download_tracks(
                 break;
             }
         }
+        all_files.push(file_name.to_string());
+        file_exists
+    };
 
-        if !file_exists {
-            let mut file_sink = file_sink_mp3::FileSinkMP3::open( // file_sink_flac::FileSinkFlac::open(
-                Some(path.to_owned()),
-                librespot::playback::config::AudioFormat::S16,
-            );
-            file_sink.add_metadata(metadata);
-            match &encoding {
-                Encoding::Flac { compression } => file_sink.set_compression(compression.unwrap_or(4)),
-                _ => {}
+    if !file_exists {
+        if let Encoding::OggVorbis = &encoding {
+            if let Err(e) = write_ogg_vorbis_passthrough(&session, track, &files, path).await {
+                log_and_skip("Ogg Vorbis stream", e);
+                bar.inc(1);
+                return;
             }
-            let (mut player, _) =
-                Player::new(player_config.clone(), session.clone(), Box::new(NoOpVolume), move || {
-                    Box::new(file_sink)
-                });
-            player.load(*track, true, 0);
-            player.await_end_of_track().await;
-            bar.set_message(&format!("{full_track_name_clean} - Encoding..."));
-            player.stop();
             bar.inc(1);
-        } else {
-            // println!("File with the same name already exists, skipping: {}", path);
+            return;
+        }
+
+        // Drive playback off the Player's event channel rather than blindly awaiting end-of-track,
+        // so a region-locked/unavailable track is skipped (after a few retries for transient
+        // failures) instead of hanging or producing a truncated file.
+        let mut attempt = 0;
+        let available = loop {
+            let (mut player, mut events) = match &encoding {
+                Encoding::Flac { compression } => {
+                    let mut file_sink = file_sink_flac::FileSinkFlac::open(
+                        Some(path.to_owned()),
+                        librespot::playback::config::AudioFormat::S16,
+                    );
+                    file_sink.set_compression(compression.unwrap_or(4));
+                    Player::new(player_config.clone(), session.clone(), Box::new(NoOpVolume), move || {
+                        Box::new(file_sink)
+                    })
+                }
+                Encoding::Mp3 { bitrate } => {
+                    let mut file_sink = file_sink_mp3::FileSinkMP3::open(
+                        Some(path.to_owned()),
+                        librespot::playback::config::AudioFormat::S16,
+                    );
+                    file_sink.set_bitrate(*bitrate);
+                    Player::new(player_config.clone(), session.clone(), Box::new(NoOpVolume), move || {
+                        Box::new(file_sink)
+                    })
+                }
+                Encoding::OggVorbis => unreachable!("handled above"),
+            };
+
+            player.load(track, true, 0);
+
+            // `ended` only becomes true on an explicit `EndOfTrack`; a closed event channel
+            // (`recv` returning `None`) without one is a load failure just like `Unavailable`,
+            // not a completed download, so it must not fall through to a successful encode.
+            let mut ended = false;
+            let mut unavailable = false;
+            while let Some(event) = events.recv().await {
+                match event {
+                    PlayerEvent::EndOfTrack { .. } => {
+                        ended = true;
+                        break;
+                    }
+                    PlayerEvent::Unavailable { .. } => {
+                        unavailable = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            if !ended {
+                unavailable = true;
+            }
+            player.stop();
+
+            if !unavailable {
+                break true;
+            }
+
+            if attempt >= retries {
+                break false;
+            }
+            attempt += 1;
+            bar.set_message(&format!("{full_track_name_clean} - Unavailable, retrying ({attempt}/{retries})..."));
+        };
+
+        if !available {
+            eprintln!("Skipping {full_track_name_clean}: track unavailable after {retries} retries");
+            // Stopping the player can make the sink write `path` even for a failed attempt, so
+            // remove it (best effort) - a leftover empty/garbage file would match the file-exists
+            // check above on a later run and hide the track forever.
+            let _ = std::fs::remove_file(path);
             bar.inc(1);
+            return;
         }
+
+        bar.set_message(&format!("{full_track_name_clean} - Encoding..."));
+        if let Err(e) = tagging::write_rich_tags(path, &metadata) {
+            eprintln!("Failed to write extended tags for {full_track_name_clean}: {e}");
+        }
+        bar.inc(1);
+    } else {
+        // println!("File with the same name already exists, skipping: {}", path);
+        bar.inc(1);
     }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_tracks(
+    session: &Session,
+    destination: PathBuf,
+    tracks: Vec<SpotifyId>,
+    ordered: bool,
+    encoding: Encoding,
+    delete_unknown_songs: bool,
+    jobs: usize,
+    retries: u32,
+) {
+    let player_config = PlayerConfig::default();
+    let bar_style = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} (ETA: {eta}) {msg}")
+        .progress_chars("##-");
+    let bar = ProgressBar::new(tracks.len() as u64);
+    bar.set_style(bar_style);
+    bar.enable_steady_tick(500);
+
+    let all_files = std::sync::Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+
+    futures::stream::iter(tracks.into_iter().enumerate())
+        .map(|(i, track)| {
+            download_one_track(
+                session.clone(),
+                player_config.clone(),
+                destination.clone(),
+                track,
+                i,
+                ordered,
+                encoding,
+                retries,
+                all_files.clone(),
+                bar.clone(),
+            )
+        })
+        .buffer_unordered(jobs)
+        .collect::<Vec<()>>()
+        .await;
 
+    let all_files = all_files.lock().unwrap();
     if delete_unknown_songs {
         for entry in destination.read_dir().unwrap() {
             let entry_path = entry.unwrap().path();
@@ -224,10 +561,23 @@ async fn download_tracks(
 #[tokio::main]
 async fn main() {
     let opt = Opt::from_args();
+    let encoding = encoding_from_opt(&opt);
 
     let session_config = SessionConfig::default();
     let cache = Cache::new(Some(opt.credentials_cache), None, None, None).unwrap();
-    let session = create_session(session_config, cache.credentials().unwrap(), Some(cache)).await;
+
+    let credentials = match &opt.username {
+        Some(username) => {
+            let password = rpassword::prompt_password(format!("Password for {username}: "))
+                .expect("Failed to read password");
+            Credentials::with_password(username, password)
+        }
+        None => cache
+            .credentials()
+            .expect("No cached credentials found; pass --username to log in interactively"),
+    };
+
+    let session = create_session(session_config, credentials, Some(cache)).await;
 
     let mut tracks: Vec<SpotifyId> = Vec::new();
 
@@ -273,9 +623,10 @@ async fn main() {
         PathBuf::from(opt.destination),
         tracks,
         opt.ordered,
-        Encoding::Mp3,
-        // opt.compression,
+        encoding,
         opt.delete_unknown_songs,
+        opt.jobs,
+        opt.retries,
     )
     .await;
 }
@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use lofty::{Accessor, MimeType, Picture, PictureType, Probe, Tag, TaggedFileExt};
+
+use crate::TrackMetadata;
+
+// Runs once the sink has finished encoding: opens the finished file with lofty and writes the
+// complete tag set - title/artist/album, track/disc numbers, the release year and embedded cover
+// art - so MP3 and FLAC outputs carry the same rich tags regardless of which encoder produced
+// them. This is the only place tags are written; the sinks themselves no longer tag their output.
+pub fn write_rich_tags(path: &str, metadata: &TrackMetadata) -> lofty::error::Result<()> {
+    let mut tagged_file = Probe::open(Path::new(path))?.read()?;
+    let tag = match tagged_file.primary_tag_mut() {
+        Some(tag) => tag,
+        None => {
+            let tag_type = tagged_file.primary_tag_type();
+            tagged_file.insert_tag(Tag::new(tag_type));
+            tagged_file.primary_tag_mut().unwrap()
+        }
+    };
+
+    tag.set_title(metadata.track_name.clone());
+    tag.set_artist(metadata.artists.join(", "));
+    tag.set_album(metadata.album.clone());
+
+    if let Some(track_number) = metadata.track_number {
+        tag.set_track(track_number);
+    }
+    if let Some(disc_number) = metadata.disc_number {
+        tag.set_disk(disc_number);
+    }
+    if let Some(year) = metadata.year {
+        tag.set_year(year as u32);
+    }
+    if let Some(cover) = &metadata.cover {
+        tag.push_picture(Picture::new_unchecked(
+            PictureType::CoverFront,
+            MimeType::Jpeg,
+            None,
+            cover.clone(),
+        ));
+    }
+
+    tagged_file.save_to_path(path)?;
+    Ok(())
+}